@@ -0,0 +1,57 @@
+use std::fmt;
+
+use failure::Fail;
+use serde::de;
+
+use httpresponse::HttpResponse;
+
+/// Implemented by errors that know how to render themselves as an HTTP
+/// response, so handlers can return `Result<T, E: ResponseError>` and let
+/// the framework convert `E` into a response instead of matching on it
+/// by hand.
+pub trait ResponseError: Fail {
+    /// Render this error as an HTTP response. Defaults to a bare 500.
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+/// Error returned by [`HttpRequest::query_as`](../struct.HttpRequest.html#method.query_as)
+/// when the query string cannot be turned into the requested type.
+#[derive(Debug)]
+pub enum QueryExtractError {
+    /// Percent-decoding the given key (or the value stored under it) failed.
+    PercentDecode(String),
+    /// The decoded query pairs could not be deserialized into the
+    /// requested type.
+    Deserialize(String),
+}
+
+impl fmt::Display for QueryExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryExtractError::PercentDecode(ref key) => {
+                write!(f, "Failed to percent-decode query key `{}`", key)
+            }
+            QueryExtractError::Deserialize(ref e) => {
+                write!(f, "Failed to deserialize query string: {}", e)
+            }
+        }
+    }
+}
+
+// `de::Error` requires `std::error::Error`; `Fail` then comes from
+// failure's blanket impl for standard errors.
+impl std::error::Error for QueryExtractError {}
+
+impl de::Error for QueryExtractError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        QueryExtractError::Deserialize(msg.to_string())
+    }
+}
+
+impl ResponseError for QueryExtractError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().body(self.to_string())
+    }
+}
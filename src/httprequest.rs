@@ -1,21 +1,24 @@
 //! HTTP Request message related code.
-use std::cell::{Ref, RefCell};
+use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::{cmp, fmt, io, str};
 
 use bytes::Bytes;
-use cookie::Cookie;
+use cookie::{Cookie, CookieJar, Key};
 use failure;
 use futures::{Async, Poll, Stream};
 use futures_cpupool::CpuPool;
 use http::{header, HeaderMap, Method, StatusCode, Uri, Version};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
 use tokio_io::AsyncRead;
+use url::percent_encoding::percent_decode;
 use url::{form_urlencoded, Url};
 
 use body::Body;
-use error::{CookieParseError, PayloadError, UrlGenerationError};
+use error::{CookieParseError, PayloadError, QueryExtractError, UrlGenerationError};
 use extensions::Extensions;
 use handler::FromRequest;
 use httpmessage::HttpMessage;
@@ -28,8 +31,105 @@ use server::message::{MessageFlags, RequestContext};
 use state::RequestState;
 use uri::Url as InnerUrl;
 
+/// Percent-decode a single query-string key or value, treating `+` as a
+/// space the way `application/x-www-form-urlencoded` does.
+fn decode_query_component(raw: &str) -> Result<String, str::Utf8Error> {
+    percent_decode(raw.replace('+', " ").as_bytes())
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+}
+
+/// Deserialize `T` directly from already percent-decoded `(key, value)`
+/// pairs, grouping repeated keys (`?tag=a&tag=b`) into a sequence. This
+/// walks the pairs `query_pairs()` already cached instead of handing a
+/// re-encoded string to `serde_urlencoded`, which would re-scan and
+/// re-percent-decode everything from scratch on every call.
+fn deserialize_query_pairs<T: DeserializeOwned>(
+    pairs: &[(String, String)],
+) -> Result<T, QueryExtractError> {
+    let mut grouped: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (key, val) in pairs {
+        match grouped.iter_mut().find(|(k, _)| *k == key.as_str()) {
+            Some(entry) => entry.1.push(val.as_str()),
+            None => grouped.push((key.as_str(), vec![val.as_str()])),
+        }
+    }
+    T::deserialize(MapDeserializer::new(
+        grouped
+            .into_iter()
+            .map(|(key, values)| (key, QueryValues(values))),
+    ))
+}
+
+/// All decoded values collected for a single query-string key. Acts as its
+/// own `serde::Deserializer` so a field typed `Vec<String>` sees a
+/// sequence while a plain `String` field just sees the first value.
+struct QueryValues<'a>(Vec<&'a str>);
+
+impl<'de> IntoDeserializer<'de, QueryExtractError> for QueryValues<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Deserializes a scalar by parsing the first value, so `?page=2` fills a
+/// `u32` field.
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident($ty:ty),)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            let raw = self.0.first().cloned().unwrap_or("");
+            visitor.$visit(raw.parse::<$ty>().map_err(<Self::Error as de::Error>::custom)?)
+        }
+    )*};
+}
+
+impl<'de> Deserializer<'de> for QueryValues<'de> {
+    type Error = QueryExtractError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.0.first().cloned().unwrap_or(""))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        SeqDeserializer::new(self.0.into_iter().map(|v| QueryValues(vec![v])))
+            .deserialize_seq(visitor)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool(bool), deserialize_char => visit_char(char),
+        deserialize_i8 => visit_i8(i8), deserialize_i16 => visit_i16(i16),
+        deserialize_i32 => visit_i32(i32), deserialize_i64 => visit_i64(i64),
+        deserialize_u8 => visit_u8(u8), deserialize_u16 => visit_u16(u16),
+        deserialize_u32 => visit_u32(u32), deserialize_u64 => visit_u64(u64),
+        deserialize_f32 => visit_f32(f32), deserialize_f64 => visit_f64(f64),
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 str string
+        bytes byte_buf unit unit_struct newtype_struct tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
 struct Query(HashMap<String, String>);
+/// Percent-decoded `(key, value)` pairs backing `query_as()`, cached the
+/// same way `Query` backs `query()` so repeated typed extraction doesn't
+/// re-scan the query string.
+struct QueryPairs(Vec<(String, String)>);
 struct Cookies(Vec<Cookie<'static>>);
+/// The unverified `CookieJar` backing `signed_cookie()`/`private_cookie()`,
+/// cached the same way `Cookies` backs `cookies()`.
+struct RequestCookieJar(CookieJar);
 struct Info(ConnectionInfo);
 
 /// An HTTP Request
@@ -91,8 +191,14 @@ impl<S> HttpRequest<S> {
 
     /// Request extensions
     #[inline]
-    pub fn extensions(&self) -> &Extensions {
-        &self.msg.inner.extensions
+    pub fn extensions(&self) -> Ref<Extensions> {
+        self.msg.inner.extensions.borrow()
+    }
+
+    /// Mutable reference to request extensions
+    #[inline]
+    fn extensions_mut(&self) -> RefMut<Extensions> {
+        self.msg.inner.extensions.borrow_mut()
     }
 
     /// Default `CpuPool`
@@ -222,19 +328,79 @@ impl<S> HttpRequest<S> {
     }
 
     /// url query parameters.
-    pub fn query(&self) -> &HashMap<String, String> {
-        unimplemented!()
-        /*
+    ///
+    /// The query string is parsed at most once per request; the result is
+    /// cached in `Extensions` and returned on subsequent calls.
+    pub fn query(&self) -> Ref<HashMap<String, String>> {
         if self.extensions().get::<Query>().is_none() {
             let mut query = HashMap::new();
             for (key, val) in form_urlencoded::parse(self.query_string().as_ref()) {
                 query.insert(key.as_ref().to_string(), val.to_string());
             }
-            let mut req = self.clone();
-            req.as_mut().extensions.insert(Query(query));
+            self.extensions_mut().insert(Query(query));
+        }
+        Ref::map(self.extensions(), |ext| &ext.get::<Query>().unwrap().0)
+    }
+
+    /// Deserialize the query string into `T`.
+    ///
+    /// Unlike [`query()`](#method.query), this supports arbitrary structs via
+    /// `serde`, including repeated keys (`?tag=a&tag=b`) collected into a
+    /// sequence field. An empty query string deserializes as an empty set
+    /// of pairs, so `T` should give its fields defaults if they are meant
+    /// to be optional.
+    ///
+    /// The query string's key/value pairs are percent-decoded and cached in
+    /// `Extensions` the first time this is called, so repeated calls (with
+    /// different `T`) don't re-scan or re-percent-decode the raw query
+    /// string; `T` is deserialized straight from the cached pairs. A key or
+    /// value whose percent-decoded bytes are not valid UTF-8 is reported as
+    /// `QueryExtractError::PercentDecode` naming the offending key; an
+    /// invalid escape such as `%zz` is kept as literal text.
+    ///
+    /// ```rust
+    /// # extern crate actix_web;
+    /// # #[macro_use] extern crate serde_derive;
+    /// # use actix_web::HttpRequest;
+    /// #[derive(Deserialize)]
+    /// struct Search {
+    ///     query: String,
+    /// }
+    ///
+    /// fn index(req: HttpRequest) -> &'static str {
+    ///     if let Ok(search) = req.query_as::<Search>() {
+    ///         // ...
+    ///     }
+    ///     "done"
+    /// }
+    /// # fn main() {}
+    /// ```
+    pub fn query_as<T: DeserializeOwned>(&self) -> Result<T, QueryExtractError> {
+        let pairs = self.query_pairs()?;
+        deserialize_query_pairs(&pairs)
+    }
+
+    /// Percent-decoded `(key, value)` pairs of the query string, cached in
+    /// `Extensions` under `QueryPairs` so `query_as()` only validates
+    /// percent-encoding once per request.
+    fn query_pairs(&self) -> Result<Ref<Vec<(String, String)>>, QueryExtractError> {
+        if self.extensions().get::<QueryPairs>().is_none() {
+            let mut pairs = Vec::new();
+            for part in self.query_string().split('&').filter(|p| !p.is_empty()) {
+                let mut kv = part.splitn(2, '=');
+                let raw_key = kv.next().unwrap_or("");
+                let raw_val = kv.next().unwrap_or("");
+                let key = decode_query_component(raw_key)
+                    .map_err(|_| QueryExtractError::PercentDecode(raw_key.to_string()))?;
+                let val = decode_query_component(raw_val)
+                    .map_err(|_| QueryExtractError::PercentDecode(key.clone()))?;
+                pairs.push((key, val));
+            }
+            self.extensions_mut().insert(QueryPairs(pairs));
         }
-        &self.extensions().get::<Query>().unwrap().0
-         */
+        Ok(Ref::map(self.extensions(), |ext| {
+            &ext.get::<QueryPairs>().unwrap().0
+        }))
     }
 
     /// The query string in the URL.
@@ -250,14 +416,13 @@ impl<S> HttpRequest<S> {
     }
 
     /// Load request cookies.
-    pub fn cookies(&self) -> Result<&Vec<Cookie<'static>>, CookieParseError> {
-        unimplemented!()
-        /*
-        if self.extensions().get::<Query>().is_none() {
-            let mut req = self.clone();
-            let msg = req.as_mut();
+    ///
+    /// The `Cookie` headers are parsed at most once per request; the
+    /// result is cached in `Extensions` and returned on subsequent calls.
+    pub fn cookies(&self) -> Result<Ref<Vec<Cookie<'static>>>, CookieParseError> {
+        if self.extensions().get::<Cookies>().is_none() {
             let mut cookies = Vec::new();
-            for hdr in msg.headers.get_all(header::COOKIE) {
+            for hdr in self.headers().get_all(header::COOKIE) {
                 let s = str::from_utf8(hdr.as_bytes()).map_err(CookieParseError::from)?;
                 for cookie_str in s.split(';').map(|s| s.trim()) {
                     if !cookie_str.is_empty() {
@@ -265,17 +430,19 @@ impl<S> HttpRequest<S> {
                     }
                 }
             }
-            msg.extensions.insert(Cookies(cookies));
+            self.extensions_mut().insert(Cookies(cookies));
         }
-        Ok(&self.extensions().get::<Cookies>().unwrap().0)*/
+        Ok(Ref::map(self.extensions(), |ext| {
+            &ext.get::<Cookies>().unwrap().0
+        }))
     }
 
     /// Return request cookie.
-    pub fn cookie(&self, name: &str) -> Option<&Cookie> {
+    pub fn cookie(&self, name: &str) -> Option<Cookie<'static>> {
         if let Ok(cookies) = self.cookies() {
-            for cookie in cookies {
+            for cookie in cookies.iter() {
                 if cookie.name() == name {
-                    return Some(cookie);
+                    return Some(cookie.clone());
                 }
             }
         }
@@ -283,9 +450,47 @@ impl<S> HttpRequest<S> {
     }
 
     pub(crate) fn set_cookies(&mut self, cookies: Option<Vec<Cookie<'static>>>) {
-        //if let Some(cookies) = cookies {
-        //self.extensions_mut().insert(Cookies(cookies));
-        //}
+        if let Some(cookies) = cookies {
+            self.extensions_mut().insert(Cookies(cookies));
+        }
+    }
+
+    /// Return a signed cookie, verifying its HMAC signature with `key`.
+    ///
+    /// `None` is returned both when the cookie is absent and when its
+    /// signature does not match, so callers can't distinguish "not set"
+    /// from "tampered with". `key` would typically be derived from
+    /// application state rather than hard-coded.
+    pub fn signed_cookie(&self, key: &Key, name: &str) -> Option<Cookie<'static>> {
+        self.cookie_jar()?.signed(key).get(name)
+    }
+
+    /// Return a private (encrypted) cookie, decrypting its value with `key`.
+    ///
+    /// `None` is returned both when the cookie is absent and when it
+    /// fails authentication or decryption.
+    pub fn private_cookie(&self, key: &Key, name: &str) -> Option<Cookie<'static>> {
+        self.cookie_jar()?.private(key).get(name)
+    }
+
+    /// Build (and cache) a `CookieJar` seeded with this request's raw
+    /// cookies, for `signed_cookie()`/`private_cookie()` to verify or
+    /// decrypt against. Parsed at most once per request.
+    fn cookie_jar(&self) -> Option<Ref<CookieJar>> {
+        if self.extensions().get::<RequestCookieJar>().is_none() {
+            // Clone out of the `Ref` (rather than holding it) so it's
+            // dropped before `extensions_mut()` takes a mutable borrow of
+            // the same `RefCell` below.
+            let cookies: Vec<Cookie<'static>> = self.cookies().ok()?.clone();
+            let mut jar = CookieJar::new();
+            for cookie in cookies {
+                jar.add_original(cookie);
+            }
+            self.extensions_mut().insert(RequestCookieJar(jar));
+        }
+        Some(Ref::map(self.extensions(), |ext| {
+            &ext.get::<RequestCookieJar>().unwrap().0
+        }))
     }
 
     /// Get a reference to the Params object.
@@ -380,6 +585,7 @@ mod tests {
     use super::*;
     use resource::ResourceHandler;
     use router::Resource;
+    use serde_derive::Deserialize;
     use test::TestRequest;
 
     #[test]
@@ -428,6 +634,90 @@ mod tests {
         assert_eq!(&query["id"], "test");
     }
 
+    #[test]
+    fn test_request_signed_cookie() {
+        let key = Key::generate();
+        let mut jar = CookieJar::new();
+        jar.signed(&key).add(Cookie::new("uid", "1"));
+        let signed = jar.get("uid").unwrap().to_string();
+
+        let req = TestRequest::default()
+            .header(header::COOKIE, signed)
+            .finish();
+
+        let cookie = req.signed_cookie(&key, "uid").unwrap();
+        assert_eq!(cookie.value(), "1");
+
+        let other_key = Key::generate();
+        assert!(req.signed_cookie(&other_key, "uid").is_none());
+        assert!(req.signed_cookie(&key, "unknown").is_none());
+    }
+
+    #[test]
+    fn test_request_private_cookie() {
+        let key = Key::generate();
+        let mut jar = CookieJar::new();
+        jar.private(&key).add(Cookie::new("uid", "1"));
+        let encrypted = jar.get("uid").unwrap().to_string();
+
+        let req = TestRequest::default()
+            .header(header::COOKIE, encrypted)
+            .finish();
+
+        let cookie = req.private_cookie(&key, "uid").unwrap();
+        assert_eq!(cookie.value(), "1");
+
+        let other_key = Key::generate();
+        assert!(req.private_cookie(&other_key, "uid").is_none());
+    }
+
+    #[test]
+    fn test_request_query_as() {
+        #[derive(Deserialize)]
+        struct Search {
+            id: String,
+        }
+
+        let req = TestRequest::with_uri("/?id=test").finish();
+        let search: Search = req.query_as().unwrap();
+        assert_eq!(search.id, "test");
+    }
+
+    #[test]
+    fn test_request_query_as_repeated() {
+        #[derive(Deserialize)]
+        struct Search {
+            tag: Vec<String>,
+        }
+
+        let req = TestRequest::with_uri("/?tag=a&tag=b").finish();
+        let search: Search = req.query_as().unwrap();
+        assert_eq!(search.tag, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn test_request_query_as_empty() {
+        let req = TestRequest::default().finish();
+        let map: HashMap<String, String> = req.query_as().unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_request_query_as_percent_decode_error() {
+        #[derive(Deserialize)]
+        struct Search {
+            id: String,
+        }
+
+        // `%ff` is a syntactically valid percent-escape that decodes to a
+        // lone non-UTF8 byte.
+        let req = TestRequest::with_uri("/?id=%ff").finish();
+        match req.query_as::<Search>() {
+            Err(QueryExtractError::PercentDecode(ref key)) => assert_eq!(key, "id"),
+            other => panic!("expected PercentDecode error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_request_match_info() {
         let mut resource = ResourceHandler::<()>::default();